@@ -1,8 +1,13 @@
 use std::path::Path;
 use std::fs::read;
+use std::io::Read;
+
+use rayon::prelude::*;
 
 use process::ImageProcessor;
 
+pub use process::{Activation, BinarizeMode, CaptchaLayout, CaptchaResult, LayerSpec};
+
 mod process;
 mod weights;
 
@@ -10,6 +15,12 @@ pub struct Parser {
     processor: ImageProcessor,
 }
 
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Parser {
     pub fn new() -> Self {
         Parser {
@@ -17,17 +28,136 @@ impl Parser {
         }
     }
 
+    /// Loads a model from a reader, e.g. a file opened with a freshly
+    /// retrained recognizer (useful once VTOP changes its captcha font),
+    /// instead of the recognizer baked into the crate.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Parser {
+            processor: ImageProcessor::from_reader(reader)?,
+        })
+    }
+
+    /// Builds a single-layer softmax-regression model from explicit weights
+    /// and biases, instead of the recognizer baked into the crate.
+    pub fn from_weights(
+        weights: Vec<Vec<f64>>,
+        biases: Vec<f64>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Parser {
+            processor: ImageProcessor::from_weights(weights, biases)?,
+        })
+    }
+
+    /// Builds a multi-layer network from a sequence of layers, e.g. one
+    /// with `Activation::Relu` hidden layers, instead of the single-layer
+    /// recognizer baked into the crate.
+    pub fn from_layers(layers: Vec<LayerSpec>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Parser {
+            processor: ImageProcessor::from_layers(layers)?,
+        })
+    }
+
+    /// Reject a parse (return an `Err`) if any recognized character's
+    /// winning confidence falls below `min_confidence`, so callers can
+    /// retry-fetch a fresh captcha instead of submitting a bad solve.
+    pub fn min_confidence(mut self, min_confidence: f64) -> Self {
+        self.processor = self.processor.with_min_confidence(min_confidence);
+        self
+    }
+
+    /// Selects the binarization strategy used when preprocessing each
+    /// character block. Defaults to `BinarizeMode::Mean`.
+    pub fn binarize_mode(mut self, binarize_mode: BinarizeMode) -> Self {
+        self.processor = self.processor.with_binarize_mode(binarize_mode);
+        self
+    }
+
+    /// Overrides the captcha geometry and character set used to segment
+    /// and decode blocks. Defaults to `CaptchaLayout::vtop()`.
+    pub fn layout(mut self, layout: CaptchaLayout) -> Self {
+        self.processor = self.processor.with_layout(layout);
+        self
+    }
+
     pub fn parse_from_file<P: AsRef<Path>>(&self, path: P) -> Result<String, Box<dyn std::error::Error>> {
         let byte_array: Vec<u8> = read(path)?;
         self.processor.process(&byte_array)
     }
 
     pub fn parse_from_base64(&self, b64_data: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let byte_array: Vec<u8> = base64::decode(b64_data)?;
+        let byte_array: Vec<u8> = decode_base64(b64_data)?;
         self.processor.process(&byte_array)
     }
 
     pub fn parse_from_bytes(&self, byte_array: &Vec<u8>) -> Result<String, Box<dyn std::error::Error>> {
         self.processor.process(&byte_array)
     }
+
+    pub fn parse_from_file_with_confidence<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<CaptchaResult, Box<dyn std::error::Error>> {
+        let byte_array: Vec<u8> = read(path)?;
+        self.processor.process_with_confidence(&byte_array)
+    }
+
+    pub fn parse_from_base64_with_confidence(
+        &self,
+        b64_data: &str,
+    ) -> Result<CaptchaResult, Box<dyn std::error::Error>> {
+        let byte_array: Vec<u8> = decode_base64(b64_data)?;
+        self.processor.process_with_confidence(&byte_array)
+    }
+
+    pub fn parse_from_bytes_with_confidence(
+        &self,
+        byte_array: &Vec<u8>,
+    ) -> Result<CaptchaResult, Box<dyn std::error::Error>> {
+        self.processor.process_with_confidence(&byte_array)
+    }
+
+    /// Parses many captchas at once, one per input, in parallel across a
+    /// rayon thread pool. Useful for bulk pre-solving or load testing
+    /// against the portal. Errors are stringified because `Box<dyn Error>`
+    /// isn't required to be `Send`/`Sync`, which rayon needs to hand results
+    /// back across threads.
+    pub fn parse_from_bytes_batch(&self, byte_arrays: &[Vec<u8>]) -> Vec<Result<String, String>> {
+        byte_arrays
+            .par_iter()
+            .map(|byte_array| self.processor.process(byte_array).map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    pub fn parse_from_base64_batch(&self, b64_data: &[&str]) -> Vec<Result<String, String>> {
+        b64_data
+            .par_iter()
+            .map(|data| {
+                let byte_array: Vec<u8> = decode_base64(data).map_err(|e| e.to_string())?;
+                self.processor.process(&byte_array).map_err(|e| e.to_string())
+            })
+            .collect()
+    }
+}
+
+/// Decodes base64 captcha data, tolerating the `data:[mime];base64,` prefix
+/// that browsers and scraping tools commonly capture, and accepting the
+/// standard or URL-safe alphabet, padded or not (URL-safe data captured from
+/// the DOM is frequently unpadded).
+fn decode_base64(data: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let data = strip_data_uri_prefix(data);
+
+    base64::decode(data)
+        .or_else(|_| base64::decode_config(data, base64::URL_SAFE))
+        .or_else(|_| base64::decode_config(data, base64::URL_SAFE_NO_PAD))
+        .or_else(|_| base64::decode_config(data, base64::STANDARD_NO_PAD))
+        .map_err(|e| format!("could not decode base64 captcha data: {}", e).into())
+}
+
+fn strip_data_uri_prefix(data: &str) -> &str {
+    if data.starts_with("data:") {
+        if let Some(idx) = data.find(";base64,") {
+            return &data[idx + ";base64,".len()..];
+        }
+    }
+    data
 }
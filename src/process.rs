@@ -1,72 +1,393 @@
+use flate2::read::{GzDecoder, ZlibDecoder};
 use image::io::Reader;
+use rayon::prelude::*;
 use serde_json::Value;
 
 use std::cmp::{max, min};
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 
 use crate::weights::DATA;
 
-const HEIGHT: usize = 40;
-const WIDTH: usize = 200;
 const CAPTCHA_CHARS: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
 
+/// Describes the geometry of a captcha image: its pixel dimensions, the
+/// allowed character set, and each character's bounding box
+/// (`x1, y1, x2, y2`) within the decoded image.
+pub struct CaptchaLayout {
+    pub width: usize,
+    pub height: usize,
+    pub chars: String,
+    pub char_boxes: Vec<(usize, usize, usize, usize)>,
+}
+
+impl CaptchaLayout {
+    /// The layout VTOP has served since this crate was written: a 200x40
+    /// image with six 24x22 character blocks, each shifted up or down by
+    /// 5px depending on parity.
+    pub fn vtop() -> Self {
+        let char_boxes = (0..6)
+            .map(|a| {
+                let x1 = (a + 1) * 25 + 2;
+                let y1 = 7 + 5 * (a % 2) + 1;
+                let x2 = (a + 2) * 25 + 1;
+                let y2 = 35 - 5 * ((a + 1) % 2);
+                (x1, y1, x2, y2)
+            })
+            .collect();
+
+        CaptchaLayout {
+            width: 200,
+            height: 40,
+            chars: CAPTCHA_CHARS.to_string(),
+            char_boxes,
+        }
+    }
+
+    /// The number of pixels a single character block is expected to
+    /// flatten to, derived from the first bounding box. Assumes all boxes
+    /// share the same size, as every shipped layout does.
+    fn block_input_size(&self) -> usize {
+        self.char_boxes
+            .first()
+            .map(|&(x1, y1, x2, y2)| (x2 - x1) * (y2 - y1))
+            .unwrap_or(0)
+    }
+}
+
+impl Default for CaptchaLayout {
+    fn default() -> Self {
+        CaptchaLayout::vtop()
+    }
+}
+
+/// Result of a confidence-aware parse: the decoded text plus the winning
+/// softmax probability for each recognized character (one per character
+/// box of the layout, six for `CaptchaLayout::vtop()`), in order.
+pub struct CaptchaResult {
+    pub text: String,
+    pub confidences: Vec<f64>,
+}
+
+/// Activation applied to a layer's output. Hidden layers typically use
+/// `Relu`; the final layer must use `Softmax` so its output is a proper
+/// probability distribution over the layout's character set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    Relu,
+    Softmax,
+}
+
+/// One layer of the recognizer network: an affine transform followed by
+/// an activation.
+pub struct LayerSpec {
+    pub weights: Vec<Vec<f64>>,
+    pub biases: Vec<f64>,
+    pub activation: Activation,
+}
+
+/// Strategy used to binarize a character block before it is fed to the
+/// network. `Mean` thresholds against the block's average intensity;
+/// `Otsu` picks the threshold that best separates the block into two
+/// classes, which holds up better against an unevenly saturated background.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum BinarizeMode {
+    #[default]
+    Mean,
+    Otsu,
+}
+
 pub struct ImageProcessor {
-    weights: Vec<Vec<f64>>,
-    biases: Vec<f64>,
+    layers: Vec<LayerSpec>,
+    min_confidence: Option<f64>,
+    binarize_mode: BinarizeMode,
+    layout: CaptchaLayout,
+}
+
+impl Default for ImageProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ImageProcessor {
+    /// Uses the recognizer baked into the crate at compile time.
     pub fn new() -> Self {
         // unwrap() is safe because parsing the hardcoded JSON data never panics.
-        let json: Value = serde_json::from_str(DATA).unwrap();
+        Self::from_json_str(DATA).unwrap()
+    }
+
+    /// Loads a model from a reader, e.g. a file opened with a freshly
+    /// retrained recognizer (useful once VTOP changes its captcha font).
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut raw = String::new();
+        reader.read_to_string(&mut raw)?;
+        Self::from_json_str(&raw)
+    }
 
-        let weights_value = json["weights"].clone();
-        let biases_value = json["biases"].clone();
+    /// Loads a model either from the legacy single-layer
+    /// `{"weights": [...], "biases": [...]}` shape used by the baked-in
+    /// recognizer, or from a multi-layer
+    /// `[{"weights": [...], "biases": [...], "activation": "relu"|"softmax"}, ...]`
+    /// array.
+    pub fn from_json_str(data: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let json: Value = serde_json::from_str(data)?;
 
-        let weights: Vec<Vec<f64>> = serde_json::from_value(weights_value).unwrap();
-        let biases: Vec<f64> = serde_json::from_value(biases_value).unwrap();
+        if let Some(layer_values) = json.as_array() {
+            let layers = layer_values
+                .iter()
+                .map(|layer| {
+                    let weights: Vec<Vec<f64>> = serde_json::from_value(layer["weights"].clone())?;
+                    let biases: Vec<f64> = serde_json::from_value(layer["biases"].clone())?;
+                    let activation = match layer["activation"].as_str() {
+                        Some("relu") => Activation::Relu,
+                        Some("softmax") | None => Activation::Softmax,
+                        Some(other) => {
+                            return Err(format!("unknown activation \"{}\"", other).into())
+                        }
+                    };
+
+                    Ok(LayerSpec {
+                        weights,
+                        biases,
+                        activation,
+                    })
+                })
+                .collect::<Result<Vec<LayerSpec>, Box<dyn std::error::Error>>>()?;
+
+            return Self::from_layers(layers);
+        }
+
+        let weights: Vec<Vec<f64>> = serde_json::from_value(json["weights"].clone())?;
+        let biases: Vec<f64> = serde_json::from_value(json["biases"].clone())?;
+
+        Self::from_weights(weights, biases)
+    }
 
-        ImageProcessor { weights, biases }
+    /// Builds a single-layer softmax-regression model.
+    pub fn from_weights(
+        weights: Vec<Vec<f64>>,
+        biases: Vec<f64>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_layers(vec![LayerSpec {
+            weights,
+            biases,
+            activation: Activation::Softmax,
+        }])
+    }
+
+    /// Builds a multi-layer network from a sequence of layers, validating
+    /// that each layer's weight/bias shapes are internally consistent, that
+    /// each layer's input size matches the previous layer's output size, and
+    /// that the first layer's input size and the last layer's output size
+    /// match the default `CaptchaLayout`. If a different layout is attached
+    /// afterwards via `with_layout`, that combination is re-checked at parse
+    /// time (in `recognize_block`).
+    pub fn from_layers(layers: Vec<LayerSpec>) -> Result<Self, Box<dyn std::error::Error>> {
+        if layers.is_empty() {
+            return Err("model must have at least one layer".into());
+        }
+
+        for (i, layer) in layers.iter().enumerate() {
+            let layer_output = layer.weights.first().map(Vec::len).unwrap_or(0);
+            if layer_output != layer.biases.len() {
+                return Err(format!(
+                    "layer {} has {} weight columns but {} biases",
+                    i, layer_output, layer.biases.len()
+                )
+                .into());
+            }
+
+            if let Some(next) = layers.get(i + 1) {
+                if next.weights.len() != layer_output {
+                    return Err(format!(
+                        "layer {} outputs {} values but layer {} expects {} weight rows",
+                        i,
+                        layer_output,
+                        i + 1,
+                        next.weights.len()
+                    )
+                    .into());
+                }
+            }
+        }
+
+        let layout = CaptchaLayout::default();
+
+        let expected_input = layout.block_input_size();
+        let first_input = layers.first().map(|l| l.weights.len()).unwrap_or(0);
+        if first_input != expected_input {
+            return Err(format!(
+                "model's first layer expects {} inputs but the default layout produces {}-pixel blocks",
+                first_input, expected_input
+            )
+            .into());
+        }
+
+        let expected_output = layout.chars.chars().count();
+        let last_output = layers.last().map(|l| l.biases.len()).unwrap_or(0);
+        if last_output != expected_output {
+            return Err(format!(
+                "model's last layer outputs {} values but the default layout has {} characters",
+                last_output, expected_output
+            )
+            .into());
+        }
+
+        Ok(ImageProcessor {
+            layers,
+            min_confidence: None,
+            binarize_mode: BinarizeMode::default(),
+            layout,
+        })
+    }
+
+    /// Reject a parse (return an `Err`) if any recognized character's
+    /// winning softmax probability falls below `min_confidence`, instead of
+    /// returning a likely-wrong guess.
+    pub fn with_min_confidence(mut self, min_confidence: f64) -> Self {
+        self.min_confidence = Some(min_confidence);
+        self
+    }
+
+    /// Selects the binarization strategy used when preprocessing each
+    /// character block. Defaults to `BinarizeMode::Mean`.
+    pub fn with_binarize_mode(mut self, binarize_mode: BinarizeMode) -> Self {
+        self.binarize_mode = binarize_mode;
+        self
+    }
+
+    /// Overrides the captcha geometry and character set used to segment
+    /// and decode blocks. Defaults to `CaptchaLayout::vtop()`.
+    pub fn with_layout(mut self, layout: CaptchaLayout) -> Self {
+        self.layout = layout;
+        self
     }
 
     pub fn process(&self, byte_array: &Vec<u8>) -> Result<String, Box<dyn std::error::Error>> {
-        let mut reader = Reader::new(Cursor::new(byte_array));
-        reader.set_format(image::ImageFormat::Jpeg);
+        Ok(self.process_with_confidence(byte_array)?.text)
+    }
+
+    pub fn process_with_confidence(
+        &self,
+        byte_array: &Vec<u8>,
+    ) -> Result<CaptchaResult, Box<dyn std::error::Error>> {
+        let inflated = inflate_if_compressed(byte_array)?;
+        let reader = Reader::new(Cursor::new(&inflated)).with_guessed_format()?;
 
         let pixels = reader.decode()?.to_rgba8().into_raw();
 
-        Ok(self.process_pixels(&pixels)?)
+        self.process_pixels(&pixels)
     }
 
-    fn process_pixels(&self, pixels: &Vec<u8>) -> Result<String, Box<dyn std::error::Error>> {
+    fn process_pixels(&self, pixels: &Vec<u8>) -> Result<CaptchaResult, Box<dyn std::error::Error>> {
         let sat = saturate(&pixels);
-        let def = de_flatten(&sat);
-        let block_list = get_blocks(&def);
+        let def = de_flatten(&sat, &self.layout);
+        let block_list = get_blocks(&def, &self.layout);
+
+        // Blocks are independent, so the per-block matrix multiplies run in
+        // parallel rather than the earlier sequential loop. Errors are plain
+        // `String`s here (rather than `Box<dyn Error>`, which isn't required
+        // to be `Send`) so rayon can hand them back across threads; `?`
+        // below converts into this function's `Box<dyn Error>` result.
+        let results: Vec<(char, f64)> = block_list
+            .par_iter()
+            .map(|block| self.recognize_block(block))
+            .collect::<Result<Vec<(char, f64)>, String>>()?;
+
+        let mut captcha_text = String::with_capacity(results.len());
+        let mut confidences: Vec<f64> = Vec::with_capacity(results.len());
+
+        for (ch, confidence) in results {
+            captcha_text.push(ch);
+            confidences.push(confidence);
+        }
 
-        let mut captcha_text = String::new();
+        Ok(CaptchaResult {
+            text: captcha_text,
+            confidences,
+        })
+    }
 
-        for block in block_list.iter() {
-            let processed: Vec<Vec<u8>> = pre_process(block);
-            let flattened: Vec<Vec<u8>> = [flatten(&processed)].to_vec();
+    fn recognize_block(&self, block: &Vec<Vec<u8>>) -> Result<(char, f64), String> {
+        let processed: Vec<Vec<u8>> = pre_process(block, self.binarize_mode);
+        let flattened: Vec<Vec<u8>> = [flatten(&processed)].to_vec();
 
-            let multiplied: Vec<Vec<f64>> = mat_multiply(&flattened, &self.weights);
-            let added: Vec<f64> = mat_add(multiplied.get(0).unwrap(), &self.biases);
+        let (first_layer, hidden_layers) = self.layers.split_first().ok_or("model has no layers")?;
 
-            let arr: Vec<f64> = softmax(&added);
-            let index_of_max: usize = arr
-                .iter()
-                .enumerate()
-                .max_by(|(_, a), (_, b)| a.total_cmp(b))
-                .map(|(index, _)| index)
-                .unwrap();
+        let expected_input = self.layout.block_input_size();
+        if first_layer.weights.len() != expected_input {
+            return Err(format!(
+                "model's first layer expects {} inputs but the configured layout produces {}-pixel blocks",
+                first_layer.weights.len(),
+                expected_input
+            ));
+        }
+
+        let multiplied: Vec<Vec<f64>> = mat_multiply(&flattened, &first_layer.weights);
+        let added: Vec<f64> = mat_add(multiplied.first().unwrap(), &first_layer.biases);
+        let mut activations: Vec<f64> = apply_activation(first_layer.activation, &added);
 
-            captcha_text += CAPTCHA_CHARS.get(index_of_max..index_of_max + 1).unwrap();
+        for layer in hidden_layers {
+            let multiplied: Vec<Vec<f64>> = mat_multiply_f64(&[activations].to_vec(), &layer.weights);
+            let added: Vec<f64> = mat_add(multiplied.first().unwrap(), &layer.biases);
+            activations = apply_activation(layer.activation, &added);
         }
 
-        Ok(captcha_text)
+        let expected_output = self.layout.chars.chars().count();
+        if activations.len() != expected_output {
+            return Err(format!(
+                "model outputs {} values but the configured layout has {} characters",
+                activations.len(),
+                expected_output
+            ));
+        }
+
+        let (index_of_max, confidence): (usize, f64) = activations
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(index, value)| (index, *value))
+            .unwrap();
+
+        if let Some(min_confidence) = self.min_confidence {
+            if confidence < min_confidence {
+                return Err(format!(
+                    "character confidence {:.3} is below the minimum of {:.3}",
+                    confidence, min_confidence
+                ));
+            }
+        }
+
+        let ch = self.layout.chars.chars().nth(index_of_max).ok_or_else(|| {
+            format!(
+                "recognized index {} is out of range for the configured character set",
+                index_of_max
+            )
+        })?;
+
+        Ok((ch, confidence))
     }
 }
 
+// VTOP occasionally serves the captcha image gzip- or zlib-wrapped; detect
+// that from the leading magic bytes and transparently inflate it before
+// handing the bytes to the image decoder.
+fn inflate_if_compressed(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if bytes.len() >= 2 && bytes[0] == 0x1f && bytes[1] == 0x8b {
+        let mut out = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut out)?;
+        return Ok(out);
+    }
+
+    if bytes.len() >= 2 && bytes[0] == 0x78 && (bytes[1] == 0x9c || bytes[1] == 0x01) {
+        let mut out = Vec::new();
+        ZlibDecoder::new(bytes).read_to_end(&mut out)?;
+        return Ok(out);
+    }
+
+    Ok(bytes.to_vec())
+}
+
 fn saturate(pixels: &Vec<u8>) -> Vec<u8> {
     let mut sat: Vec<u8> = Vec::with_capacity(pixels.len() / 4);
 
@@ -94,22 +415,22 @@ fn saturate(pixels: &Vec<u8>) -> Vec<u8> {
 }
 
 fn flatten(block: &Vec<Vec<u8>>) -> Vec<u8> {
-    let mut flattened: Vec<u8> = Vec::with_capacity(block.len() * block.get(0).unwrap().len());
+    let mut flattened: Vec<u8> = Vec::with_capacity(block.len() * block.first().unwrap().len());
     for i in 0..block.len() {
-        for j in 0..block.get(0).unwrap().len() {
+        for j in 0..block.first().unwrap().len() {
             flattened.push(*(block.get(i).unwrap().get(j).unwrap()));
         }
     }
     return flattened;
 }
 
-fn de_flatten(saturated: &Vec<u8>) -> Vec<Vec<u8>> {
-    let mut de_flattened: Vec<Vec<u8>> = Vec::with_capacity(HEIGHT);
+fn de_flatten(saturated: &Vec<u8>, layout: &CaptchaLayout) -> Vec<Vec<u8>> {
+    let mut de_flattened: Vec<Vec<u8>> = Vec::with_capacity(layout.height);
 
-    for i in 0..HEIGHT {
-        let mut arr: Vec<u8> = Vec::with_capacity(WIDTH);
-        for j in 0..WIDTH {
-            arr.push(*(saturated.get(i * WIDTH + j).unwrap()));
+    for i in 0..layout.height {
+        let mut arr: Vec<u8> = Vec::with_capacity(layout.width);
+        for j in 0..layout.width {
+            arr.push(*(saturated.get(i * layout.width + j).unwrap()));
         }
         de_flattened.push(arr);
     }
@@ -117,49 +438,34 @@ fn de_flatten(saturated: &Vec<u8>) -> Vec<Vec<u8>> {
     return de_flattened;
 }
 
-fn get_blocks(deflatted: &Vec<Vec<u8>>) -> Vec<Vec<Vec<u8>>> {
-    let mut blocks_list: Vec<Vec<Vec<u8>>> = Vec::with_capacity(6);
-
-    let mut a: usize = 0;
-
-    while a < 6 {
-        let x1 = (a + 1) * 25 + 2;
-        let y1 = 7 + 5 * (a % 2) + 1;
-
-        let x2 = (a + 2) * 25 + 1;
-        let y2 = 35 - 5 * ((a + 1) % 2);
-
-        blocks_list.push(
+fn get_blocks(deflatted: &Vec<Vec<u8>>, layout: &CaptchaLayout) -> Vec<Vec<Vec<u8>>> {
+    layout
+        .char_boxes
+        .iter()
+        .map(|&(x1, y1, x2, y2)| {
             deflatted[y1..y2]
                 .to_vec()
                 .iter()
                 .map(|s| s[x1..x2].to_vec())
-                .collect(),
-        );
-        a += 1;
-    }
-
-    return blocks_list;
+                .collect()
+        })
+        .collect()
 }
 
-fn pre_process(block: &Vec<Vec<u8>>) -> Vec<Vec<u8>> {
-    let mut avg: f64 = 0.0;
-
-    for i in block.iter() {
-        for j in i.iter() {
-            avg += *j as f64;
-        }
-    }
+fn pre_process(block: &Vec<Vec<u8>>, mode: BinarizeMode) -> Vec<Vec<u8>> {
+    let threshold: u8 = match mode {
+        BinarizeMode::Mean => mean_threshold(block),
+        BinarizeMode::Otsu => otsu_threshold(block),
+    };
 
-    avg = avg / (24 * 22) as f64;
     let mut processed: Vec<Vec<u8>> = Vec::with_capacity(block.len());
 
     for i in 0..block.len() {
-        let len = block.get(0).unwrap().len();
+        let len = block.first().unwrap().len();
         let mut arr: Vec<u8> = Vec::with_capacity(len);
 
         for j in 0..len {
-            if *(block.get(i).unwrap().get(j).unwrap()) > (avg as u8) {
+            if *(block.get(i).unwrap().get(j).unwrap()) > threshold {
                 arr.push(1);
             } else {
                 arr.push(0);
@@ -171,10 +477,74 @@ fn pre_process(block: &Vec<Vec<u8>>) -> Vec<Vec<u8>> {
     return processed;
 }
 
+fn mean_threshold(block: &Vec<Vec<u8>>) -> u8 {
+    let mut avg: f64 = 0.0;
+    let mut count: usize = 0;
+
+    for i in block.iter() {
+        for j in i.iter() {
+            avg += *j as f64;
+            count += 1;
+        }
+    }
+
+    (avg / count as f64) as u8
+}
+
+// Otsu's method: sweep every candidate threshold and keep the one that
+// maximizes the between-class variance of the block's intensity histogram.
+fn otsu_threshold(block: &Vec<Vec<u8>>) -> u8 {
+    let mut histogram = [0usize; 256];
+    let mut total: usize = 0;
+
+    for row in block.iter() {
+        for value in row.iter() {
+            histogram[*value as usize] += 1;
+            total += 1;
+        }
+    }
+
+    let probabilities: Vec<f64> = histogram
+        .iter()
+        .map(|&count| count as f64 / total as f64)
+        .collect();
+    let mu_total: f64 = probabilities
+        .iter()
+        .enumerate()
+        .map(|(i, p)| i as f64 * p)
+        .sum();
+
+    let mut w0 = 0.0;
+    let mut sum0 = 0.0;
+    let mut best_threshold: u8 = 0;
+    let mut best_variance = -1.0;
+
+    for t in 0..256 {
+        w0 += probabilities[t];
+        sum0 += t as f64 * probabilities[t];
+        let w1 = 1.0 - w0;
+
+        if w0 == 0.0 || w1 == 0.0 {
+            continue;
+        }
+
+        let mu0 = sum0 / w0;
+        let mu1 = (mu_total - sum0) / w1;
+        let variance = w0 * w1 * (mu0 - mu1).powi(2);
+
+        if variance > best_variance {
+            best_variance = variance;
+            best_threshold = t as u8;
+        }
+    }
+
+    best_threshold
+}
+
 fn mat_multiply(matrix: &Vec<Vec<u8>>, weights: &Vec<Vec<f64>>) -> Vec<Vec<f64>> {
     let x = matrix.len();
-    let z = matrix.get(0).unwrap().len();
-    let y = weights.get(0).unwrap().len();
+    let z = matrix.first().unwrap().len();
+    let y = weights.first().unwrap().len();
 
     assert!(weights.len() == z);
 
@@ -192,6 +562,34 @@ fn mat_multiply(matrix: &Vec<Vec<u8>>, weights: &Vec<Vec<f64>>) -> Vec<Vec<f64>>
     return product;
 }
 
+fn mat_multiply_f64(matrix: &Vec<Vec<f64>>, weights: &Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+    let x = matrix.len();
+    let z = matrix.first().unwrap().len();
+    let y = weights.first().unwrap().len();
+
+    assert!(weights.len() == z);
+
+    let product_row: Vec<f64> = vec![0.0; y];
+    let mut product: Vec<Vec<f64>> = vec![product_row; x];
+
+    for i in 0..x {
+        for j in 0..y {
+            for k in 0..z {
+                product[i][j] += matrix[i][k] * weights[k][j];
+            }
+        }
+    }
+
+    return product;
+}
+
+fn apply_activation(activation: Activation, values: &Vec<f64>) -> Vec<f64> {
+    match activation {
+        Activation::Relu => values.iter().map(|v| v.max(0.0)).collect(),
+        Activation::Softmax => softmax(values),
+    }
+}
+
 fn mat_add(first: &Vec<f64>, second: &Vec<f64>) -> Vec<f64> {
     let len = first.len();
     let mut arr: Vec<f64> = Vec::with_capacity(len);
@@ -203,16 +601,11 @@ fn mat_add(first: &Vec<f64>, second: &Vec<f64>) -> Vec<f64> {
 }
 
 fn softmax(arg: &Vec<f64>) -> Vec<f64> {
-    let mut n_arr = arg.clone();
-    let mut s: f64 = 0.0;
-
-    for i in n_arr.iter() {
-        s += i.exp();
-    }
-
-    for i in 0..arg.len() {
-        n_arr.push((arg.get(i).unwrap().exp()) / s);
-    }
+    // Subtract the max logit before exponentiating so a confident, large
+    // logit (as a multi-layer network can produce) doesn't overflow to inf.
+    let max = arg.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let exps: Vec<f64> = arg.iter().map(|v| (v - max).exp()).collect();
+    let s: f64 = exps.iter().sum();
 
-    return n_arr;
+    return exps.iter().map(|v| v / s).collect();
 }